@@ -1,6 +1,5 @@
 //! Pre/post cache/request hooks
 
-use client_query::ClientQuery;
 use dnssector::{DNSSector, ParsedPacket};
 use dnssector::c_abi::{self, FnTable};
 use glob::glob;
@@ -9,15 +8,29 @@ use libloading::{self, Library};
 use libloading::os::unix::Symbol;
 #[cfg(windows)]
 use libloading::os::windows::Symbol;
-use nix::libc::{c_int, c_void};
+use nix::libc::c_int;
 use qp_trie::Trie;
-use std::ffi::OsStr;
 use std::mem;
 use std::path::PathBuf;
 use std::sync::Arc;
+// The WebAssembly backend targets the pre-`anyhow` wasmtime API: it uses
+// `Trap::new`, `Store::add_fuel`, `Linker::instantiate` and
+// `Instance::get_memory` with their `0.2x`-era signatures. The crate manifest
+// must therefore pin `wasmtime = "0.27"` / `wasmtime-wasi = "0.27"` (the last
+// series exposing these) alongside `serde_json` for the control interface;
+// newer releases moved to `anyhow::Error` and a `Store`-less fuel API and will
+// not compile against this file.
+use wasmtime::{Caller, Engine, Extern, Linker, Module, Store, Trap};
+use wasmtime_wasi::{WasiCtx, WasiCtxBuilder};
 
 const MASTER_SERVICE_LIBRARY_NAME: &'static str = "master";
 const DLL_EXT: &'static str = "dylib";
+const WASM_EXT: &'static str = "wasm";
+
+/// Amount of fuel granted to an untrusted WebAssembly hook before it is
+/// interrupted. A hook that burns through its fuel is treated as if it had
+/// returned `Action::Drop`, so a runaway guest can never stall the daemon.
+const WASM_HOOK_FUEL: u64 = 1_000_000_000;
 
 #[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
 pub struct SessionState;
@@ -27,6 +40,7 @@ pub enum Action {
     Pass = 1,
     Lookup,
     Drop,
+    Synth,
 }
 
 impl From<Action> for c_int {
@@ -40,6 +54,7 @@ impl From<c_int> for Action {
         match id {
             x if x == Action::Pass.into() => Action::Pass,
             x if x == Action::Lookup.into() => Action::Lookup,
+            x if x == Action::Synth.into() => Action::Synth,
             _ => Action::Drop,
         }
     }
@@ -53,14 +68,285 @@ pub enum Stage {
 
 type HookSymbolClientT = unsafe extern "C" fn(*const FnTable, *mut ParsedPacket) -> c_int;
 
-struct ServiceHooks {
+/// Hooks backed by a native shared object resolved through the `libloading`
+/// C ABI.
+struct NativeServiceHooks {
     library: Arc<Library>,
     hook_recv: Option<Symbol<HookSymbolClientT>>,
     hook_deliver: Option<Symbol<HookSymbolClientT>>,
 }
 
+/// Hooks backed by a WebAssembly module executed in an embedded `wasmtime`
+/// runtime. The compiled module is kept resident; a fresh, fuel-metered store
+/// is created for every packet so guests cannot share state across requests.
+struct WasmServiceHooks {
+    engine: Engine,
+    module: Module,
+    hook_recv: bool,
+    hook_deliver: bool,
+}
+
+/// A loaded service exposes its hooks either through a native dynamic library
+/// or through a sandboxed WebAssembly module.
+enum ServiceHooks {
+    Native(NativeServiceHooks),
+    Wasm(WasmServiceHooks),
+}
+
 struct Service {
     service_hooks: Option<ServiceHooks>,
+    library_path: Option<String>,
+}
+
+/// A snapshot of a loaded service, reported by the control interface's
+/// `service.list` method.
+pub struct ServiceStatus {
+    pub id: String,
+    pub recv: bool,
+    pub deliver: bool,
+}
+
+/// Host state threaded through a single WebAssembly hook invocation. It holds
+/// the guest's WASI context alongside the raw wire packet the hook operates
+/// on. The guest reads and replaces that buffer through the `packet_len`,
+/// `packet_read` and `packet_write` host imports, so it has byte-level access
+/// to the whole packet — enough to author a complete reply for
+/// `Action::Synth` — without the daemon guessing a memory layout on its behalf.
+struct WasmHostState {
+    wasi: WasiCtx,
+    packet: Vec<u8>,
+}
+
+impl ServiceHooks {
+    /// Whether this backend implements the given stage.
+    fn implements(&self, stage: Stage) -> bool {
+        match *self {
+            ServiceHooks::Native(ref hooks) => match stage {
+                Stage::Recv => hooks.hook_recv.is_some(),
+                Stage::Deliver => hooks.hook_deliver.is_some(),
+            },
+            ServiceHooks::Wasm(ref hooks) => match stage {
+                Stage::Recv => hooks.hook_recv,
+                Stage::Deliver => hooks.hook_deliver,
+            },
+        }
+    }
+
+    /// Run the hook for `stage` against `parsed_packet`, returning the action
+    /// the service requested together with the (possibly rewritten) wire
+    /// packet. Native hooks go through the C ABI directly; WebAssembly hooks
+    /// are run in a fuel-metered sandbox where any trap or fuel exhaustion is
+    /// surfaced as `Action::Drop`.
+    fn run(&self, stage: Stage, mut parsed_packet: ParsedPacket) -> (Action, Vec<u8>) {
+        match *self {
+            ServiceHooks::Native(ref hooks) => {
+                let hook = match stage {
+                    Stage::Recv => hooks.hook_recv.as_ref().unwrap(),
+                    Stage::Deliver => hooks.hook_deliver.as_ref().unwrap(),
+                };
+                let fn_table = c_abi::fn_table();
+                let action = unsafe { hook(&fn_table, &mut parsed_packet) }.into();
+                (action, parsed_packet.into_packet())
+            }
+            ServiceHooks::Wasm(ref hooks) => hooks.run(stage, parsed_packet),
+        }
+    }
+}
+
+impl WasmServiceHooks {
+    fn new(library_path: &str) -> Result<WasmServiceHooks, &'static str> {
+        let mut config = wasmtime::Config::new();
+        config.consume_fuel(true);
+        let engine = match Engine::new(&config) {
+            Ok(engine) => engine,
+            Err(e) => {
+                error!("Cannot initialize the wasm engine [{}]", e);
+                return Err("Unable to initialize the wasm engine");
+            }
+        };
+        let module = match Module::from_file(&engine, library_path) {
+            Ok(module) => module,
+            Err(e) => {
+                error!("Cannot load the wasm module [{}] [{}]", library_path, e);
+                return Err("Unable to load the wasm module");
+            }
+        };
+        let hook_recv = module.get_export("hook_recv").is_some();
+        let hook_deliver = module.get_export("hook_deliver").is_some();
+        Ok(WasmServiceHooks {
+            engine,
+            module,
+            hook_recv,
+            hook_deliver,
+        })
+    }
+
+    /// Instantiate the module with a WASI context and the `edgedns` packet
+    /// host imports, copy the wire packet into the guest, invoke the exported
+    /// hook and read the (possibly grown) mutated buffer back out. A trap, a
+    /// fuel exhaustion, or a guest that returns bytes `DNSSector` cannot parse
+    /// all surface as `Action::Drop` with the original packet preserved.
+    fn run(&self, stage: Stage, parsed_packet: ParsedPacket) -> (Action, Vec<u8>) {
+        let export = match stage {
+            Stage::Recv => "hook_recv",
+            Stage::Deliver => "hook_deliver",
+        };
+        let original = parsed_packet.into_packet();
+        match self.invoke(export, original.clone()) {
+            Ok((action, packet)) => match DNSSector::new(packet.clone()).and_then(|ds| ds.parse()) {
+                Ok(_) => (action, packet),
+                Err(e) => {
+                    warn!("Wasm hook returned an unparsable packet, dropping: {}", e);
+                    (Action::Drop, original)
+                }
+            },
+            Err(e) => {
+                warn!("Wasm hook trapped, dropping: {}", e);
+                (Action::Drop, original)
+            }
+        }
+    }
+
+    fn invoke(&self, export: &str, packet: Vec<u8>) -> Result<(Action, Vec<u8>), Trap> {
+        let wasi = WasiCtxBuilder::new().build();
+        let mut store = Store::new(&self.engine, WasmHostState { wasi, packet });
+        store
+            .add_fuel(WASM_HOOK_FUEL)
+            .map_err(|e| Trap::new(e.to_string()))?;
+
+        let mut linker = Linker::new(&self.engine);
+        wasmtime_wasi::add_to_linker(&mut linker, |state: &mut WasmHostState| &mut state.wasi)
+            .map_err(|e| Trap::new(e.to_string()))?;
+        Self::link_host_functions(&mut linker)?;
+
+        let instance = linker
+            .instantiate(&mut store, &self.module)
+            .map_err(|e| Trap::new(e.to_string()))?;
+        let memory = instance
+            .get_memory(&mut store, "memory")
+            .ok_or_else(|| Trap::new("Guest does not export a linear memory"))?;
+
+        // Copy the wire packet into a guest-owned allocation and hand the hook
+        // its address and length.
+        let len = store.data().packet.len() as u32;
+        let ptr = Self::guest_alloc(&mut store, &instance, len)?;
+        {
+            let packet = mem::replace(&mut store.data_mut().packet, Vec::new());
+            memory
+                .write(&mut store, ptr as usize, &packet)
+                .map_err(|e| Trap::new(e.to_string()))?;
+            store.data_mut().packet = packet;
+        }
+
+        let hook = instance
+            .get_typed_func::<(u32, u32), i32, _>(&mut store, export)
+            .map_err(|e| Trap::new(e.to_string()))?;
+        let sent = store.data().packet.clone();
+        let code = hook.call(&mut store, (ptr, len))?;
+        let action = Action::from(code as c_int);
+
+        // A guest that replaced the packet with different bytes through
+        // `packet_write` has authored its result on the host side; return it.
+        if store.data().packet != sent {
+            let packet = mem::replace(&mut store.data_mut().packet, Vec::new());
+            return Ok((action, packet));
+        }
+
+        // Otherwise read the mutated buffer back out. The guest reports its
+        // final length through `hook_packet_len`, falling back to the original
+        // length when it does not export one.
+        let out_len = Self::guest_packet_len(&mut store, &instance)?.unwrap_or(len);
+        let mut packet = vec![0u8; out_len as usize];
+        memory
+            .read(&store, ptr as usize, &mut packet)
+            .map_err(|e| Trap::new(e.to_string()))?;
+        Ok((action, packet))
+    }
+
+    /// Register the `edgedns` host imports `packet_len`, `packet_read` and
+    /// `packet_write`. They operate on the raw wire packet held in host state,
+    /// so a guest can read it and replace it — up to a full synthesized reply.
+    /// Access is byte-level only: none of the record-level operations of
+    /// `c_abi::fn_table()` is exposed, and a guest parses and builds records
+    /// itself.
+    fn link_host_functions(linker: &mut Linker<WasmHostState>) -> Result<(), Trap> {
+        linker
+            .func_wrap("edgedns", "packet_len", |caller: Caller<WasmHostState>| {
+                caller.data().packet.len() as u32
+            })
+            .map_err(|e| Trap::new(e.to_string()))?;
+        // Copy up to `len` packet bytes into the guest at `ptr`, returning the
+        // number written (or -1 when the guest memory is unavailable).
+        linker
+            .func_wrap(
+                "edgedns",
+                "packet_read",
+                |mut caller: Caller<WasmHostState>, ptr: u32, len: u32| -> i32 {
+                    let memory = match caller.get_export("memory") {
+                        Some(Extern::Memory(memory)) => memory,
+                        _ => return -1,
+                    };
+                    let packet = mem::replace(&mut caller.data_mut().packet, Vec::new());
+                    let len = (len as usize).min(packet.len());
+                    let res = memory.write(&mut caller, ptr as usize, &packet[..len]);
+                    caller.data_mut().packet = packet;
+                    match res {
+                        Ok(()) => len as i32,
+                        Err(_) => -1,
+                    }
+                },
+            )
+            .map_err(|e| Trap::new(e.to_string()))?;
+        // Replace the host-side packet with `len` bytes read from the guest at
+        // `ptr`, returning the number read (or -1 on a bad range).
+        linker
+            .func_wrap(
+                "edgedns",
+                "packet_write",
+                |mut caller: Caller<WasmHostState>, ptr: u32, len: u32| -> i32 {
+                    let memory = match caller.get_export("memory") {
+                        Some(Extern::Memory(memory)) => memory,
+                        _ => return -1,
+                    };
+                    let mut packet = vec![0u8; len as usize];
+                    if memory.read(&caller, ptr as usize, &mut packet).is_err() {
+                        return -1;
+                    }
+                    caller.data_mut().packet = packet;
+                    len as i32
+                },
+            )
+            .map_err(|e| Trap::new(e.to_string()))?;
+        Ok(())
+    }
+
+    /// Allocate `len` bytes inside the guest for the inbound wire packet. An
+    /// untrusted module must export a `hook_alloc(len) -> ptr` function; we
+    /// refuse to scribble over its data or stack at a guessed address.
+    fn guest_alloc(
+        store: &mut Store<WasmHostState>,
+        instance: &wasmtime::Instance,
+        len: u32,
+    ) -> Result<u32, Trap> {
+        let alloc = instance
+            .get_typed_func::<u32, u32, _>(&mut *store, "hook_alloc")
+            .map_err(|_| Trap::new("Guest does not export the required `hook_alloc` function"))?;
+        alloc.call(store, len)
+    }
+
+    /// Ask the guest for the final packet length after it has run. A guest that
+    /// grows the packet must export `hook_packet_len() -> len`; otherwise the
+    /// original length is assumed (`None`). A trap inside `hook_packet_len` is
+    /// returned as an error.
+    fn guest_packet_len(
+        store: &mut Store<WasmHostState>,
+        instance: &wasmtime::Instance,
+    ) -> Result<Option<u32>, Trap> {
+        match instance.get_typed_func::<(), u32, _>(&mut *store, "hook_packet_len") {
+            Ok(f) => f.call(store, ()).map(Some),
+            Err(_) => Ok(None),
+        }
+    }
 }
 
 pub struct Hooks {
@@ -75,10 +361,18 @@ impl Service {
             None => {
                 return Ok(Service {
                     service_hooks: None,
+                    library_path: None,
                 })
             }
             Some(library_path) => library_path,
         };
+        if library_path.ends_with(&format!(".{}", WASM_EXT)) {
+            let wasm_hooks = WasmServiceHooks::new(library_path)?;
+            return Ok(Service {
+                service_hooks: Some(ServiceHooks::Wasm(wasm_hooks)),
+                library_path: Some(library_path.to_owned()),
+            });
+        }
         let library = match Library::new(library_path) {
             Err(e) => {
                 error!("Cannot load the dynamic library [{}] [{}]", library_path, e);
@@ -97,13 +391,14 @@ impl Service {
             unsafe { library_inner.get(b"hook_deliver") };
         let hook_deliver = hook_deliver_hl.ok().map(|hook| unsafe { hook.into_raw() });
 
-        let service_hooks = ServiceHooks {
+        let service_hooks = NativeServiceHooks {
             library,
             hook_recv,
             hook_deliver,
         };
         Ok(Service {
-            service_hooks: Some(service_hooks),
+            service_hooks: Some(ServiceHooks::Native(service_hooks)),
+            library_path: Some(library_path.to_owned()),
         })
     }
 }
@@ -114,47 +409,222 @@ impl Hooks {
             None => return Err("Missing stem from file name"),
             Some(stem) => stem,
         };
+        let stem = match stem.to_str() {
+            None => return Err("Unsupported path name"),
+            Some(stem) => stem,
+        };
         debug!("Loading dynamic library [{}]", library_path.display());
-        let services = &mut self.services;
+        // The master library handles every query that no zone library claims
+        // and is keyed by the empty service id; any other library registers as
+        // a zone suffix keyed by its file stem so it only runs for queries at
+        // or below that zone.
         let service_id = if stem == MASTER_SERVICE_LIBRARY_NAME {
             info!("Loading master dynamic library");
-            &self.master_service_id
+            self.master_service_id.clone()
         } else {
-            match stem.to_str() {
-                None => return Err("Unsupported path name"),
-                Some(stem) => stem.as_bytes(),
-            }
+            info!("Loading zone dynamic library for [{}]", stem);
+            Self::zone_key(stem.as_bytes())
         };
-        if stem == MASTER_SERVICE_LIBRARY_NAME {
-            let library_path_str = match library_path.to_str() {
-                None => return Err("Unsupported path name"),
-                Some(path_str) => path_str,
-            };
-            let service = match Service::new(Some(library_path_str)) {
-                Ok(service) => service,
-                Err(_) => return Err("Unable to register the service"),
-            };
-            services.insert(service_id.to_vec(), service);
+        let library_path_str = match library_path.to_str() {
+            None => return Err("Unsupported path name"),
+            Some(path_str) => path_str,
+        };
+        let service = match Service::new(Some(library_path_str)) {
+            Ok(service) => service,
+            Err(_) => return Err("Unable to register the service"),
+        };
+        self.services.insert(service_id, service);
+        Ok(())
+    }
+
+    /// Encode a DNS name as a trie key that orders by zone specificity. The
+    /// name is lowercased and its labels are reversed, each terminated by a
+    /// NUL byte (`example.com` → `com\0example\0`). Because `qp_trie` matches
+    /// on byte prefixes, the longest stored key that is a prefix of a query's
+    /// key is its most specific enclosing zone.
+    fn zone_key(name: &[u8]) -> Vec<u8> {
+        Self::zone_key_from_labels(name.split(|&b| b == b'.').filter(|l| !l.is_empty()))
+    }
+
+    /// Encode a DNS name that is already in wire form — a sequence of
+    /// length-prefixed labels, optionally terminated by the root (a zero-length
+    /// label) — as a zone key. `ParsedPacket::question()` returns the qname in
+    /// this raw wire format rather than as dotted text, so selection must
+    /// decode it here instead of splitting on `.`. A malformed name (a label
+    /// length running past the end of the buffer) yields an empty key, which
+    /// falls back to the master service.
+    fn zone_key_wire(name: &[u8]) -> Vec<u8> {
+        let mut labels: Vec<&[u8]> = Vec::new();
+        let mut pos = 0;
+        while pos < name.len() {
+            let len = name[pos] as usize;
+            if len == 0 {
+                break;
+            }
+            let start = pos + 1;
+            let end = start + len;
+            if end > name.len() {
+                return Vec::new();
+            }
+            labels.push(&name[start..end]);
+            pos = end;
+        }
+        Self::zone_key_from_labels(labels.into_iter())
+    }
+
+    /// Build a zone key (lowercased, reversed, NUL-terminated labels) from an
+    /// iterator of raw labels.
+    fn zone_key_from_labels<'a, I>(labels: I) -> Vec<u8>
+    where
+        I: Iterator<Item = &'a [u8]>,
+    {
+        let mut labels: Vec<&[u8]> = labels.collect();
+        labels.reverse();
+        let mut key = Vec::new();
+        for label in labels {
+            key.extend(label.iter().map(u8::to_ascii_lowercase));
+            key.push(0);
+        }
+        key
+    }
+
+    /// Look up the service whose stored zone key is the longest prefix of
+    /// `key`, falling back to the master service when none matches.
+    fn lookup(&self, key: &[u8]) -> Option<&Service> {
+        // Walk back through label boundaries, longest first, so the most
+        // specific registered zone wins.
+        let mut end = key.len();
+        while end > 0 {
+            if let Some(service) = self.services.get(&key[..end]) {
+                return Some(service);
+            }
+            end -= 1;
+            while end > 0 && key[end - 1] != 0 {
+                end -= 1;
+            }
         }
+        self.services.get(&self.master_service_id[..])
+    }
+
+    /// Select the service whose zone is the most specific suffix of the raw
+    /// wire-format `qname`, falling back to the master service when no zone
+    /// matches.
+    fn select_service(&self, qname: &[u8]) -> Option<&Service> {
+        self.lookup(&Self::zone_key_wire(qname))
+    }
+
+    /// Select the service that should handle `parsed_packet`, keyed on its
+    /// query name. Queries without a question fall back to the master service.
+    fn select_for(&self, parsed_packet: &ParsedPacket) -> Option<&Service> {
+        match parsed_packet.question() {
+            Some((ref name, _, _)) => self.select_service(name),
+            None => self.services.get(&self.master_service_id[..]),
+        }
+    }
+
+    /// Map a textual service id to its trie key. The reserved `master` id maps
+    /// to the empty key; everything else is treated as a zone name.
+    fn service_key(&self, service_id: &str) -> Vec<u8> {
+        if service_id == MASTER_SERVICE_LIBRARY_NAME {
+            self.master_service_id.clone()
+        } else {
+            Self::zone_key(service_id.as_bytes())
+        }
+    }
+
+    /// Recover the textual service id from a trie key (inverse of
+    /// [`zone_key`]).
+    fn service_id(key: &[u8]) -> String {
+        if key.is_empty() {
+            return MASTER_SERVICE_LIBRARY_NAME.to_owned();
+        }
+        let mut labels: Vec<String> = key
+            .split(|&b| b == 0)
+            .filter(|l| !l.is_empty())
+            .map(|l| String::from_utf8_lossy(l).into_owned())
+            .collect();
+        labels.reverse();
+        labels.join(".")
+    }
+
+    /// Load (or replace) a service at runtime, keyed by `service_id`.
+    pub fn load_service(
+        &mut self,
+        service_id: &str,
+        library_path: &str,
+    ) -> Result<(), &'static str> {
+        let service = Service::new(Some(library_path))?;
+        self.services.insert(self.service_key(service_id), service);
         Ok(())
     }
 
+    /// Unload a previously loaded service, returning an error when it is not
+    /// currently registered.
+    pub fn unload_service(&mut self, service_id: &str) -> Result<(), &'static str> {
+        let key = self.service_key(service_id);
+        match self.services.remove(&key) {
+            Some(_) => Ok(()),
+            None => Err("Unknown service"),
+        }
+    }
+
+    /// Reload a service, dropping the old `Arc<Library>` and re-resolving its
+    /// hooks. When `library_path` is omitted the service's existing path is
+    /// reused.
+    pub fn reload_service(
+        &mut self,
+        service_id: &str,
+        library_path: Option<&str>,
+    ) -> Result<(), &'static str> {
+        let key = self.service_key(service_id);
+        let path = match library_path {
+            Some(path) => path.to_owned(),
+            None => match self.services.get(&key) {
+                Some(&Service {
+                    library_path: Some(ref path),
+                    ..
+                }) => path.clone(),
+                _ => return Err("Unknown service or missing library path"),
+            },
+        };
+        let service = Service::new(Some(&path))?;
+        self.services.insert(key, service);
+        Ok(())
+    }
+
+    /// Report every loaded service and the stages it implements.
+    pub fn list_services(&self) -> Vec<ServiceStatus> {
+        self.services
+            .iter()
+            .map(|(key, service)| {
+                let hooks = service.service_hooks.as_ref();
+                ServiceStatus {
+                    id: Self::service_id(key),
+                    recv: hooks.map_or(false, |h| h.implements(Stage::Recv)),
+                    deliver: hooks.map_or(false, |h| h.implements(Stage::Deliver)),
+                }
+            })
+            .collect()
+    }
+
     fn load_libraries(&mut self) {
-        let path_expr = {
-            let libraries_path = match self.libraries_path {
-                None => return,
-                Some(ref libraries_path) => libraries_path,
-            };
-            format!("{}/*.{}", libraries_path, DLL_EXT)
+        let libraries_path = match self.libraries_path {
+            None => return,
+            Some(ref libraries_path) => libraries_path.clone(),
         };
-        for library_path in glob(&path_expr).expect("Unsupported path for dynamic libraries") {
-            let library_path = match library_path {
-                Err(_) => continue,
-                Ok(ref library_path) => library_path,
-            };
-            match self.load_library(&library_path) {
-                Ok(()) => {}
-                Err(e) => warn!("[{}]: {}", library_path.display(), e),
+        // `glob` does not support brace alternation, so sweep once per
+        // supported extension.
+        for ext in &[DLL_EXT, WASM_EXT] {
+            let path_expr = format!("{}/*.{}", libraries_path, ext);
+            for library_path in glob(&path_expr).expect("Unsupported path for dynamic libraries") {
+                let library_path = match library_path {
+                    Err(_) => continue,
+                    Ok(ref library_path) => library_path,
+                };
+                match self.load_library(&library_path) {
+                    Ok(()) => {}
+                    Err(e) => warn!("[{}]: {}", library_path.display(), e),
+                }
             }
         }
     }
@@ -172,14 +642,24 @@ impl Hooks {
     }
 
     #[inline]
-    pub fn enabled(&self, _stage: Stage) -> bool {
-        let service = self.services.get(&self.master_service_id);
-        service
-            .expect("Nonexistent service")
-            .service_hooks
-            .is_some()
+    pub fn enabled(&self, stage: Stage) -> bool {
+        self.services.iter().any(|(_, service)| {
+            service
+                .service_hooks
+                .as_ref()
+                .map_or(false, |service_hooks| service_hooks.implements(stage))
+        })
     }
 
+    /// Run the client-side hook for `stage` and return the action it
+    /// requested together with the (possibly rewritten) packet. An
+    /// `Action::Synth` means the returned packet is already a complete
+    /// response (QR bit set) that should be sent straight back to the client,
+    /// bypassing the cache and any upstream resolution. Both backends can
+    /// author such a response: native hooks through `c_abi::fn_table()` and
+    /// wasm hooks by rewriting the whole buffer via `packet_write`, so a wasm
+    /// guest can return e.g. a static A record for split-horizon, not just a
+    /// header-only NXDOMAIN/REFUSED.
     pub fn apply_clientside(
         &self,
         session_state: SessionState,
@@ -196,28 +676,28 @@ impl Hooks {
                 return Err("Cannot parse packet");
             }
         };
-        let mut parsed_packet = match ds.parse() {
+        let parsed_packet = match ds.parse() {
             Ok(parsed_packet) => parsed_packet,
             Err(e) => {
                 warn!("Invalid packet: {}", e);
                 return Err("Invalid packet");
             }
         };
-        let service = self.services
-            .get(&self.master_service_id)
-            .expect("Nonexistent master service");
-        let service_hooks = service.service_hooks.as_ref().unwrap();
-        let hook = match stage {
-            Stage::Recv => service_hooks.hook_recv.as_ref().unwrap(),
-            Stage::Deliver => service_hooks.hook_deliver.as_ref().unwrap(),
+        let service = match self.select_for(&parsed_packet) {
+            Some(service) => service,
+            None => return Ok((Action::Pass, parsed_packet.into_packet())),
         };
-        let fn_table = c_abi::fn_table();
-        let action = unsafe { hook(&fn_table, &mut parsed_packet) }.into();
-
-        let packet = parsed_packet.into_packet();
+        let service_hooks = match service.service_hooks.as_ref() {
+            Some(service_hooks) if service_hooks.implements(stage) => service_hooks,
+            _ => return Ok((Action::Pass, parsed_packet.into_packet())),
+        };
+        let (action, packet) = service_hooks.run(stage, parsed_packet);
         Ok((action, packet))
     }
 
+    /// Run the server-side hook for `stage`. As with [`apply_clientside`], an
+    /// `Action::Synth` means the returned packet is a finished response to be
+    /// delivered without a further cache lookup or upstream forward.
     pub fn apply_serverside(
         &self,
         packet: Vec<u8>,
@@ -233,24 +713,267 @@ impl Hooks {
                 return Err("Cannot parse packet");
             }
         };
-        let mut parsed_packet = match ds.parse() {
+        let parsed_packet = match ds.parse() {
             Ok(parsed_packet) => parsed_packet,
             Err(e) => {
                 warn!("Invalid packet: {}", e);
                 return Err("Invalid packet");
             }
         };
-        let service = self.services
-            .get(&self.master_service_id)
-            .expect("Nonexistent master service");
-        let service_hooks = service.service_hooks.as_ref().unwrap();
-        let hook = match stage {
-            Stage::Recv => service_hooks.hook_recv.as_ref().unwrap(),
-            Stage::Deliver => service_hooks.hook_deliver.as_ref().unwrap(),
+        let service = match self.select_for(&parsed_packet) {
+            Some(service) => service,
+            None => return Ok((Action::Pass, parsed_packet.into_packet())),
+        };
+        let service_hooks = match service.service_hooks.as_ref() {
+            Some(service_hooks) if service_hooks.implements(stage) => service_hooks,
+            _ => return Ok((Action::Pass, parsed_packet.into_packet())),
         };
-        let fn_table = c_abi::fn_table();
-        let action = unsafe { hook(&fn_table, &mut parsed_packet) }.into();
-        let packet = parsed_packet.into_packet();
+        let (action, packet) = service_hooks.run(stage, parsed_packet);
         Ok((action, packet))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Encode dotted text as a raw wire-format DNS name (length-prefixed
+    /// labels terminated by the root), matching what `question()` returns.
+    fn wire(name: &str) -> Vec<u8> {
+        let mut out = Vec::new();
+        for label in name.split('.').filter(|l| !l.is_empty()) {
+            out.push(label.len() as u8);
+            out.extend_from_slice(label.as_bytes());
+        }
+        out.push(0);
+        out
+    }
+
+    fn empty_hooks() -> Hooks {
+        Hooks {
+            services: Trie::new(),
+            master_service_id: Vec::new(),
+            libraries_path: None,
+        }
+    }
+
+    fn marked_service(mark: &str) -> Service {
+        Service {
+            service_hooks: None,
+            library_path: Some(mark.to_owned()),
+        }
+    }
+
+    #[test]
+    fn action_round_trips_through_c_int() {
+        for action in &[Action::Pass, Action::Lookup, Action::Drop, Action::Synth] {
+            let code: c_int = (*action).into();
+            assert_eq!(Action::from(code), *action);
+        }
+        // Synth must decode to itself, not collapse into the Drop fallback.
+        let synth: c_int = Action::Synth.into();
+        assert_eq!(Action::from(synth), Action::Synth);
+        // Unknown codes remain Drop.
+        assert_eq!(Action::from(999), Action::Drop);
+    }
+
+    #[test]
+    fn zone_key_round_trips_through_service_id() {
+        let key = Hooks::zone_key(b"Example.COM");
+        assert_eq!(key, b"com\0example\0");
+        assert_eq!(Hooks::service_id(&key), "example.com");
+        assert_eq!(Hooks::service_id(&[]), MASTER_SERVICE_LIBRARY_NAME);
+    }
+
+    #[test]
+    fn wire_and_dotted_keys_agree() {
+        assert_eq!(
+            Hooks::zone_key_wire(&wire("example.com")),
+            Hooks::zone_key(b"example.com")
+        );
+        // A label length running past the end of the buffer is rejected.
+        assert!(Hooks::zone_key_wire(&[5, b'a']).is_empty());
+    }
+
+    #[test]
+    fn select_service_prefers_most_specific_zone() {
+        let mut hooks = empty_hooks();
+        hooks.services.insert(Vec::new(), marked_service("master"));
+        hooks
+            .services
+            .insert(Hooks::zone_key(b"example.com"), marked_service("zone"));
+
+        // A subdomain and the apex both select the zone service.
+        for qname in &["www.example.com", "example.com"] {
+            let service = hooks.select_service(&wire(qname)).unwrap();
+            assert_eq!(service.library_path.as_deref(), Some("zone"));
+        }
+        // A name outside the zone falls back to master.
+        let service = hooks.select_service(&wire("example.org")).unwrap();
+        assert_eq!(service.library_path.as_deref(), Some("master"));
+    }
+}
+
+/// Control interface: a small JSON-RPC server over a local Unix socket that
+/// lets operators load, unload, reload and list hook services without
+/// restarting the daemon. The `Hooks` state is shared behind an `RwLock` so
+/// mutating methods take the write lock while `service.list` only needs a
+/// read lock.
+pub mod control {
+    use super::Hooks;
+    use serde_json::{json, Value};
+    use std::fs;
+    use std::io::{self, BufRead, BufReader, Write};
+    use std::os::unix::net::{UnixListener, UnixStream};
+    use std::sync::{Arc, RwLock};
+    use std::thread;
+
+    /// Bind the control socket at `socket_path` and serve requests on a
+    /// background thread. Any stale socket left over from a previous run is
+    /// removed first.
+    pub fn listen(hooks: Arc<RwLock<Hooks>>, socket_path: &str) -> io::Result<()> {
+        let _ = fs::remove_file(socket_path);
+        let listener = UnixListener::bind(socket_path)?;
+        info!("Control interface listening on [{}]", socket_path);
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                match stream {
+                    Ok(stream) => {
+                        let hooks = hooks.clone();
+                        thread::spawn(move || serve(&hooks, stream));
+                    }
+                    Err(e) => warn!("Control connection failed: {}", e),
+                }
+            }
+        });
+        Ok(())
+    }
+
+    /// Handle one connection: newline-delimited JSON-RPC requests in, one
+    /// response object per line out.
+    fn serve(hooks: &Arc<RwLock<Hooks>>, stream: UnixStream) {
+        let mut writer = match stream.try_clone() {
+            Ok(writer) => writer,
+            Err(e) => {
+                warn!("Control connection failed: {}", e);
+                return;
+            }
+        };
+        let reader = BufReader::new(stream);
+        for line in reader.lines() {
+            let line = match line {
+                Ok(line) => line,
+                Err(_) => break,
+            };
+            if line.trim().is_empty() {
+                continue;
+            }
+            let response = match serde_json::from_str::<Value>(&line) {
+                Ok(request) => dispatch(hooks, &request),
+                Err(e) => json!({ "id": Value::Null, "error": e.to_string() }),
+            };
+            if writeln!(writer, "{}", response).is_err() {
+                break;
+            }
+        }
+    }
+
+    /// Route a single JSON-RPC request to the matching `Hooks` method.
+    fn dispatch(hooks: &Arc<RwLock<Hooks>>, request: &Value) -> Value {
+        let id = request.get("id").cloned().unwrap_or(Value::Null);
+        let params = &request["params"];
+        let service = params["service"].as_str();
+        let path = params["path"].as_str();
+        let result: Result<Value, String> = match request["method"].as_str().unwrap_or("") {
+            "service.load" => match (service, path) {
+                (Some(service), Some(path)) => hooks
+                    .write()
+                    .unwrap()
+                    .load_service(service, path)
+                    .map(|()| json!("ok"))
+                    .map_err(str::to_owned),
+                _ => Err("`service` and `path` are required".to_owned()),
+            },
+            "service.unload" => match service {
+                Some(service) => hooks
+                    .write()
+                    .unwrap()
+                    .unload_service(service)
+                    .map(|()| json!("ok"))
+                    .map_err(str::to_owned),
+                None => Err("`service` is required".to_owned()),
+            },
+            "service.reload" => match service {
+                Some(service) => hooks
+                    .write()
+                    .unwrap()
+                    .reload_service(service, path)
+                    .map(|()| json!("ok"))
+                    .map_err(str::to_owned),
+                None => Err("`service` is required".to_owned()),
+            },
+            "service.list" => Ok(Value::Array(
+                hooks
+                    .read()
+                    .unwrap()
+                    .list_services()
+                    .into_iter()
+                    .map(|status| {
+                        json!({
+                            "service": status.id,
+                            "recv": status.recv,
+                            "deliver": status.deliver,
+                        })
+                    })
+                    .collect(),
+            )),
+            other => Err(format!("Unknown method [{}]", other)),
+        };
+        match result {
+            Ok(result) => json!({ "id": id, "result": result }),
+            Err(error) => json!({ "id": id, "error": error }),
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        fn hooks() -> Arc<RwLock<Hooks>> {
+            Arc::new(RwLock::new(Hooks::new(None)))
+        }
+
+        #[test]
+        fn unknown_method_is_an_error() {
+            let response = dispatch(&hooks(), &json!({ "id": 1, "method": "service.nope" }));
+            assert_eq!(response["id"], json!(1));
+            assert!(response["error"].is_string());
+            assert!(response.get("result").is_none());
+        }
+
+        #[test]
+        fn load_requires_service_and_path() {
+            let response = dispatch(
+                &hooks(),
+                &json!({ "id": 2, "method": "service.load", "params": { "service": "z" } }),
+            );
+            assert!(response["error"].is_string());
+        }
+
+        #[test]
+        fn unload_unknown_service_is_an_error() {
+            let response = dispatch(
+                &hooks(),
+                &json!({ "method": "service.unload", "params": { "service": "absent" } }),
+            );
+            assert_eq!(response["error"], json!("Unknown service"));
+            assert_eq!(response["id"], Value::Null);
+        }
+
+        #[test]
+        fn list_reports_an_empty_array_when_nothing_is_loaded() {
+            let response = dispatch(&hooks(), &json!({ "id": 3, "method": "service.list" }));
+            assert_eq!(response["result"], json!([]));
+        }
+    }
+}